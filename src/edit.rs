@@ -1,35 +1,348 @@
-// edit
-// * insert
-// * replace
-
-// 
-//   - {edit: /root/.bashrc, regex: '|^(export PATH.*)|\1:/opt/<%=distro%>/bin|'}
-//   - {edit: /etc/skel/.bashrc, regex: '|^(export PATH.*)|\1:/opt/<%=distro%>/bin|'}
-
-// {edit: /etc/sudoers, insert: append,  "builder ALL=(ALL) NOPASSWD: ALL"}
-// {edit: /root/.bashrc, regex: '|^(export PATH.*)|\1:/opt/<%=distro%>/bin|'}
-// {edit: /etc/skel/.bashrc, regex: '|^(export PATH.*)|\1:/opt/<%=distro%>/bin|'}
-// {edit: /etc/hosts, insert: append,  '127.0.0.1 localhost'}
-//   - edit: /etc/locale.conf insert: append values:
-//       - 'LANG=<%=language%>.<%=character_set%>'
-//       - 'LANGUAGE=<%=language%>.<%=character_set%>'
-//   - {edit: /etc/locale.gen, regex: '|^#(<%=language%>\..*)|\1|'}
-//  - {edit: /etc/profile.d/locale.sh, insert: append,  'export LC_COLLATE=C'}
-//   - {edit: /etc/profile.d/locale.sh, insert: append,  'export
-//     LC_ALL=<%=language%>.<%=character_set%>'}
-//   - edit: /etc/lsb-release insert: append values:
-//       - 'LSB_VERSION=1.4'
-//       - 'DISTRIB_ID=<%=distro%>'
-//       - 'DISTRIB_RELEASE=rolling'
-// - 'DISTRIB_DESCRIPTION=<%=distro%>'
-
-//       # Minimal amount of swapping without disabling it entirely
-//       - {edit: '/etc/sysctl.d/10-<%=distro%>.conf', insert: append,  "vm.swappiness = 1"}
-//       # Enable kernel ipv4 forwarding for containers
-//       - {edit: '/etc/sysctl.d/10-<%=distro%>.conf', insert: append,  "net.ipv4.ip_forward = 1"}
-//       # Disable ipv6 forwarding
-//       - {edit: '/etc/sysctl.d/10-<%=distro%>.conf', insert: append, "net.ipv6.conf.all.forwarding
-//         = 0"}
-//       # Increase the number of user file watches to max
-//       - {edit: '/etc/sysctl.d/10-<%=distro%>.conf', insert: append,  "fs.inotify.max_user_watches
-//         = 524288"}
+//! Declarative edit manifest support
+//!
+//! A manifest is simply a list of `Edit` directives, e.g. deserialized from YAML:
+//! ```yaml
+//! - {path: /etc/hosts, op: append, lines: ['127.0.0.1 localhost']}
+//! - {path: /root/.bashrc, op: replace, regex: '^(export PATH.*)', value: '${1}:/opt/<%=distro%>/bin'}
+//! ```
+//! `<%=name%>` tokens in any regex or value are expanded against a caller supplied set of
+//! variables before the directive is run, so a single manifest can be reused across targets
+//! e.g. `distro`, `language`, `character_set`.
+use std::{collections::HashMap, path::PathBuf};
+
+use serde::Deserialize;
+
+use crate::*;
+
+/// A single declarative edit directive to apply against a file in the VFS
+///
+/// ### Examples
+/// ```
+/// use rivia_file::prelude::*;
+///
+/// let edit = file::Edit { path: "file1".into(), op: file::EditOp::Append { lines: vec!["foo".into()] } };
+/// assert_eq!(edit.path, std::path::PathBuf::from("file1"));
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Edit
+{
+    /// Path of the file to edit
+    pub path: PathBuf,
+
+    /// Operation to perform against `path`
+    #[serde(flatten)]
+    pub op: EditOp,
+}
+
+/// The operation to perform as part of an `Edit` directive
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "op")]
+pub enum EditOp
+{
+    /// Replace all matches of `regex` with `value`
+    ///
+    /// * Supports the same bash like variable expansion in `value` as `replace_all`
+    /// * `<%=name%>` tokens are expanded before the directive runs; the expanded value is inserted
+    ///   literally, it does not participate in `replace_all`'s bash like `$1`/`${name}` expansion
+    Replace
+    {
+        regex: String, value: String
+    },
+
+    /// Insert `lines` at the location determined by `regex` and `offset`
+    ///
+    /// * Insert will be before the regex location match. Use offset=1 to insert after match
+    Insert
+    {
+        lines: Vec<String>, regex: String, offset: isize
+    },
+
+    /// Append `lines` to the end of the file
+    Append
+    {
+        lines: Vec<String>
+    },
+
+    /// Prepend `lines` to the beginning of the file
+    Prepend
+    {
+        lines: Vec<String>
+    },
+}
+
+/// Expand `<%=name%>` tokens in `value` using the given variables
+///
+/// * Performs a straight textual substitution, unknown tokens are left as is
+fn expand<T: AsRef<str>>(value: T, vars: &HashMap<String, String>) -> String
+{
+    let mut value = value.as_ref().to_string();
+    for (name, val) in vars {
+        value = value.replace(&format!("<%={}%>", name), val);
+    }
+    value
+}
+
+/// Expand `<%=name%>` tokens in a regex pattern using the given variables
+///
+/// * Escapes each variable's value so regex metacharacters it contains (e.g. `.` in a hostname) are
+///   matched literally rather than changing the pattern's match semantics
+fn expand_regex<T: AsRef<str>>(value: T, vars: &HashMap<String, String>) -> String
+{
+    let mut value = value.as_ref().to_string();
+    for (name, val) in vars {
+        value = value.replace(&format!("<%={}%>", name), &regex::escape(val));
+    }
+    value
+}
+
+/// Expand `<%=name%>` tokens in a `replace_all` value using the given variables
+///
+/// * Escapes `$` in each variable's value so it's inserted literally rather than being interpreted
+///   as a capture group reference
+fn expand_value<T: AsRef<str>>(value: T, vars: &HashMap<String, String>) -> String
+{
+    let mut value = value.as_ref().to_string();
+    for (name, val) in vars {
+        value = value.replace(&format!("<%={}%>", name), &val.replace('$', "$$"));
+    }
+    value
+}
+
+/// Apply a single edit directive against the VFS
+///
+/// * Expands `<%=name%>` tokens in `edit.path` and in every regex/value using `vars` before the
+///   directive runs, so a manifest path like `/etc/sysctl.d/10-<%=distro%>.conf` resolves per target
+/// * Handles path expansion and absolute path resolution via the underlying `file`/`vfs` calls
+///
+/// ### Examples
+/// ```
+/// use rivia_file::prelude::*;
+/// use std::collections::HashMap;
+///
+/// assert!(vfs::set_memfs().is_ok());
+/// let file = vfs::root().mash("file");
+/// assert!(vfs::write_all(&file, "foo").is_ok());
+///
+/// let edit = file::Edit {
+///     path: file.clone(),
+///     op: file::EditOp::Replace { regex: "foo".into(), value: "<%=name%>".into() },
+/// };
+/// let mut vars = HashMap::new();
+/// vars.insert("name".to_string(), "bar".to_string());
+/// assert!(file::apply(&edit, &vars).is_ok());
+/// assert_read_all!(&file, "bar");
+/// ```
+pub fn apply(edit: &Edit, vars: &HashMap<String, String>) -> RvResult<()>
+{
+    let path = PathBuf::from(expand(edit.path.to_string_lossy(), vars));
+    match &edit.op {
+        EditOp::Replace { regex, value } => {
+            replace_all(&path, expand_regex(regex, vars), expand_value(value, vars))?;
+        },
+        EditOp::Insert { lines, regex, offset } => {
+            let lines = lines.iter().map(|x| expand(x, vars)).collect::<Vec<String>>();
+            insert_lines(&path, &lines, expand_regex(regex, vars), *offset)?;
+        },
+        EditOp::Append { lines } => {
+            let lines = lines.iter().map(|x| expand(x, vars)).collect::<Vec<String>>();
+            vfs::append_lines(&path, &lines)?;
+        },
+        EditOp::Prepend { lines } => {
+            let mut lines = lines.iter().map(|x| expand(x, vars)).collect::<Vec<String>>();
+            let mut existing = vfs::read_lines(&path)?;
+            lines.append(&mut existing);
+            vfs::write_lines(&path, &lines)?;
+        },
+    }
+    Ok(())
+}
+
+/// Apply a list of edit directives against the VFS in order
+///
+/// * Expands `<%=name%>` tokens in every regex and value using `vars` before each directive runs
+/// * Stops at the first failure so a partially applied manifest is debuggable; the error message
+///   includes the index and path of the directive that failed
+///
+/// ### Examples
+/// ```
+/// use rivia_file::prelude::*;
+/// use std::collections::HashMap;
+///
+/// assert!(vfs::set_memfs().is_ok());
+/// let file = vfs::root().mash("file");
+/// assert!(vfs::append_lines(&file, &["foo"]).is_ok());
+///
+/// let edits = vec![
+///     file::Edit { path: file.clone(), op: file::EditOp::Append { lines: vec!["bar".into()] } },
+///     file::Edit { path: file.clone(), op: file::EditOp::Prepend { lines: vec!["baz".into()] } },
+/// ];
+/// assert!(file::apply_all(&edits, &HashMap::new()).is_ok());
+/// assert_eq!(vfs::read_lines(&file).unwrap(), vec!["baz".to_string(), "foo".to_string(), "bar".to_string()]);
+/// ```
+pub fn apply_all(edits: &[Edit], vars: &HashMap<String, String>) -> RvResult<()>
+{
+    for (i, edit) in edits.iter().enumerate() {
+        apply(edit, vars)
+            .map_err(|err| RvError::from(format!("edit {} for {} failed: {}", i, edit.path.display(), err).as_str()))?;
+    }
+    Ok(())
+}
+
+// Unit tests
+// -------------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests
+{
+    use std::collections::HashMap;
+
+    use crate::prelude::*;
+
+    #[test]
+    fn test_apply_replace_with_expansion()
+    {
+        let tmpdir = assert_memfs_setup!();
+        let file = tmpdir.mash("file");
+        assert_write_all!(&file, "foo");
+
+        let edit = file::Edit {
+            path: file.clone(),
+            op: file::EditOp::Replace { regex: "foo".into(), value: "<%=name%>".into() },
+        };
+        let mut vars = HashMap::new();
+        vars.insert("name".to_string(), "bar".to_string());
+        assert!(file::apply(&edit, &vars).is_ok());
+        assert_read_all!(&file, "bar");
+
+        assert_remove_all!(&tmpdir);
+    }
+
+    #[test]
+    fn test_apply_replace_value_dollar_sign_is_literal()
+    {
+        let tmpdir = assert_memfs_setup!();
+        let file = tmpdir.mash("file");
+        assert_write_all!(&file, "hello");
+
+        let edit = file::Edit {
+            path: file.clone(),
+            op: file::EditOp::Replace { regex: "hello".into(), value: "<%=price%>".into() },
+        };
+        let mut vars = HashMap::new();
+        vars.insert("price".to_string(), "$5".to_string());
+        assert!(file::apply(&edit, &vars).is_ok());
+        assert_read_all!(&file, "$5");
+
+        assert_remove_all!(&tmpdir);
+    }
+
+    #[test]
+    fn test_apply_regex_metacharacters_are_literal()
+    {
+        let tmpdir = assert_memfs_setup!();
+        let file = tmpdir.mash("file");
+        assert!(vfs::append_lines(&file, &["a.b.c", "aXbYc"]).is_ok());
+
+        let edit = file::Edit {
+            path: file.clone(),
+            op: file::EditOp::Replace { regex: "<%=pat%>".into(), value: "MATCH".into() },
+        };
+        let mut vars = HashMap::new();
+        vars.insert("pat".to_string(), "a.b.c".to_string());
+        assert!(file::apply(&edit, &vars).is_ok());
+        assert_eq!(vfs::read_lines(&file).unwrap(), vec!["MATCH".to_string(), "aXbYc".to_string()]);
+
+        assert_remove_all!(&tmpdir);
+    }
+
+    #[test]
+    fn test_apply_expands_path_tokens()
+    {
+        let tmpdir = assert_memfs_setup!();
+        let file = tmpdir.mash("10-arch.conf");
+        assert_write_all!(&file, "foo");
+
+        let edit = file::Edit {
+            path: tmpdir.mash("10-<%=distro%>.conf"),
+            op: file::EditOp::Replace { regex: "foo".into(), value: "bar".into() },
+        };
+        let mut vars = HashMap::new();
+        vars.insert("distro".to_string(), "arch".to_string());
+        assert!(file::apply(&edit, &vars).is_ok());
+        assert_read_all!(&file, "bar");
+
+        assert_remove_all!(&tmpdir);
+    }
+
+    #[test]
+    fn test_apply_insert()
+    {
+        let tmpdir = assert_memfs_setup!();
+        let file = tmpdir.mash("file");
+        assert!(vfs::append_lines(&file, &["foo3"]).is_ok());
+
+        let edit = file::Edit {
+            path: file.clone(),
+            op: file::EditOp::Insert { lines: vec!["foo1".into(), "foo2".into()], regex: "foo3".into(), offset: 0 },
+        };
+        assert!(file::apply(&edit, &HashMap::new()).is_ok());
+        assert_eq!(vfs::read_lines(&file).unwrap(), vec!["foo1".to_string(), "foo2".to_string(), "foo3".to_string()]);
+
+        assert_remove_all!(&tmpdir);
+    }
+
+    #[test]
+    fn test_apply_append_and_prepend()
+    {
+        let tmpdir = assert_memfs_setup!();
+        let file = tmpdir.mash("file");
+        assert!(vfs::append_lines(&file, &["foo"]).is_ok());
+
+        let edits = vec![
+            file::Edit { path: file.clone(), op: file::EditOp::Append { lines: vec!["bar".into()] } },
+            file::Edit { path: file.clone(), op: file::EditOp::Prepend { lines: vec!["baz".into()] } },
+        ];
+        assert!(file::apply_all(&edits, &HashMap::new()).is_ok());
+        assert_eq!(vfs::read_lines(&file).unwrap(), vec!["baz".to_string(), "foo".to_string(), "bar".to_string()]);
+
+        assert_remove_all!(&tmpdir);
+    }
+
+    #[test]
+    fn test_apply_all_reports_failing_index_and_path()
+    {
+        let tmpdir = assert_memfs_setup!();
+        let file = tmpdir.mash("file");
+        assert_write_all!(&file, "foo");
+
+        let edits = vec![
+            file::Edit { path: file.clone(), op: file::EditOp::Append { lines: vec!["bar".into()] } },
+            file::Edit {
+                path: file.clone(),
+                op: file::EditOp::Insert { lines: vec!["x".into()], regex: "[".into(), offset: 0 },
+            },
+        ];
+        let err = file::apply_all(&edits, &HashMap::new()).unwrap_err().to_string();
+        assert!(err.contains(&format!("edit 1 for {}", file.display())));
+
+        assert_remove_all!(&tmpdir);
+    }
+
+    #[test]
+    fn test_deserialize_edit_manifest()
+    {
+        let yaml = "
+- path: /etc/hosts
+  op: append
+  lines:
+    - '127.0.0.1 localhost'
+- path: /root/.bashrc
+  op: replace
+  regex: '^(export PATH.*)'
+  value: '${1}:/opt/<%=distro%>/bin'
+";
+        let edits: Vec<file::Edit> = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(edits.len(), 2);
+        assert_eq!(edits[0].path, PathBuf::from("/etc/hosts"));
+        assert_eq!(edits[0].op, file::EditOp::Append { lines: vec!["127.0.0.1 localhost".to_string()] });
+    }
+}
@@ -9,6 +9,8 @@ mod edit;
 use regex::Regex;
 use rivia_vfs::prelude::*;
 
+pub use edit::{Edit, EditOp, apply, apply_all};
+
 /// All essential symbols in a simple consumable form
 ///
 /// ### Examples